@@ -1,29 +1,293 @@
+use blake2s_simd::Params as Blake2s;
+use memmap::Mmap;
 use rand::{ChaChaRng, Rng, SeedableRng};
-use serde::{Deserialize, Serialize};
-use serde_json;
 use std::cmp;
-use std::fs::metadata;
+use std::convert::TryInto;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
 use storage_proofs::crypto::feistel;
 
+/// Selects the parent-ordering rules `bucketsample_parents` (and, for the
+/// invariant it establishes, `Graph::parents`) use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// The original bucket-sampling behavior: sampled parents may repeat
+    /// each other or `node - 1`, and `node - 1` isn't specially favored.
+    V1,
+    /// Guarantees `node - 1` is a base parent for every `node >= 2`. The
+    /// remaining sampled parents are raised to at least
+    /// `MIN_BASE_PARENT_NODE` (for `node >= MIN_BASE_PARENT_NODE +
+    /// base_degree`) and de-duplicated, so the 5 base slots never collide
+    /// once `node >= base_degree`.
+    V1_1,
+}
+
+/// Floor for the sampled (non-`node - 1`) base parents under
+/// `ApiVersion::V1_1`: sampled values below it are raised to it before
+/// de-duplication. Applies only to nodes at or above
+/// `MIN_BASE_PARENT_NODE + base_degree`; smaller nodes search down to 0.
+const MIN_BASE_PARENT_NODE: usize = 2;
+
+fn domain_separated_digest(tag: &[u8], porep_id: &[u8]) -> [u8; 32] {
+    let mut state = Blake2s::new().hash_length(32).to_state();
+    state.update(tag);
+    state.update(porep_id);
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(state.finalize().as_bytes());
+    digest
+}
+
+/// Derive the expander graph's Feistel permutation keys from `porep_id`,
+/// so the expander topology is actually bound to the sector/prover
+/// rather than every graph sharing the fixed keys `[1, 2, 3, 4]`.
+pub fn derive_feistel_keys(porep_id: &[u8]) -> [u32; 4] {
+    let digest = domain_separated_digest(b"FEISTEL", porep_id);
+    let mut keys = [0u32; 4];
+    for (key, chunk) in keys.iter_mut().zip(digest.chunks_exact(4)) {
+        *key = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    keys
+}
+
+/// Derive the DRG bucket-sample seed from `porep_id`, so back-sampling is
+/// bound to the sector/prover rather than every graph using the same
+/// fixed seed.
+pub fn derive_drg_seed(porep_id: &[u8]) -> [u32; 7] {
+    let digest = domain_separated_digest(b"DRSAMPLE", porep_id);
+    let mut seed = [0u32; 7];
+    for (word, chunk) in seed.iter_mut().zip(digest.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    seed
+}
+
+/// Rough per-entry overhead (beyond the raw parent indices) of a cached
+/// expander adjacency: the node-index tag, the `Vec` header, and the
+/// `Option`/slot bookkeeping around it.
+const EXPANDER_CACHE_ENTRY_OVERHEAD: usize = 24;
+
+/// Direct-mapped cache over expander-graph parent/child lookups.
+///
+/// `Graph::parents` is called in strictly increasing node order while
+/// replicating forward layers and strictly decreasing order on reverse
+/// layers, so a handful of recently-computed slots gets a near-100% hit
+/// rate without ever materializing the `O(nodes)` adjacency lists that
+/// `gen_parents_cache` used to build up front.
+#[derive(Default)]
+struct ExpanderCache {
+    slots: Vec<Option<(usize, Vec<usize>)>>,
+}
+
+impl ExpanderCache {
+    fn ensure_capacity(&mut self, capacity: usize) {
+        if self.slots.len() != capacity {
+            self.slots = vec![None; cmp::max(capacity, 1)];
+        }
+    }
+
+    fn get_or_insert_with(&mut self, node: usize, compute: impl FnOnce() -> Vec<usize>) -> Vec<usize> {
+        let slot = node % self.slots.len();
+        if let Some((cached_node, value)) = &self.slots[slot] {
+            if *cached_node == node {
+                return value.clone();
+            }
+        }
+        let value = compute();
+        self.slots[slot] = Some((node, value.clone()));
+        value
+    }
+}
+
+/// Lazily-initialized, mutex-guarded state backing the expander caches.
+///
+/// None of this is worth persisting: the Feistel precompute is cheap to
+/// redo, and the caches themselves hold no more than a few recently-seen
+/// windows.
+#[derive(Default)]
+struct ExpanderState {
+    feistel_precomputed: Option<feistel::FeistelPrecomputed>,
+    parents: ExpanderCache,
+    children: ExpanderCache,
+}
+
+/// Translate a MiB budget into a node-entry count for the expander
+/// caches, given how many `usize` indices each node's adjacency holds.
+fn expander_cache_capacity(expansion_degree: usize, cache_mib: usize) -> usize {
+    let bytes_per_entry =
+        expansion_degree * std::mem::size_of::<usize>() + EXPANDER_CACHE_ENTRY_OVERHEAD;
+    let budget_bytes = cache_mib * 1024 * 1024;
+    cmp::max(budget_bytes / cmp::max(bytes_per_entry, 1), 1)
+}
+
+/// Magic number identifying the on-disk base/DRG parents cache format
+/// (ASCII "R2PC" as a big-endian `u32`; it is stored little-endian, so
+/// the file's first four bytes read "CP2R").
+const CACHE_MAGIC: u32 = 0x5232_5043;
+/// Bumped whenever the on-disk row layout changes, so a stale cache file
+/// from an older build is regenerated instead of misread.
+const CACHE_FORMAT_VERSION: u32 = 1;
+/// `bucketsample_parents` always returns exactly this many entries.
+const BASE_PARENTS_PER_NODE: usize = 5;
+const CACHE_ROW_BYTES: usize = BASE_PARENTS_PER_NODE * 4;
+// magic(4) + version(4) + nodes(8) + base_degree(4) + expansion_degree(4) + seed(7*4) + api_version(4)
+const CACHE_HEADER_BYTES: usize = 4 + 4 + 8 + 4 + 4 + 7 * 4 + 4;
+
+fn api_version_tag(api_version: ApiVersion) -> u32 {
+    match api_version {
+        ApiVersion::V1 => 0,
+        ApiVersion::V1_1 => 1,
+    }
+}
+
+/// Name a cache file by hashing the graph parameters it was generated
+/// for, so graphs with different `nodes`/`base_degree`/`expansion_degree`/
+/// `seed`/`api_version` get distinct files instead of colliding on a
+/// single `g.json`.
+fn cache_file_path(
+    nodes: usize,
+    base_degree: usize,
+    expansion_degree: usize,
+    seed: &[u32; 7],
+    api_version: ApiVersion,
+) -> PathBuf {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher as _};
+
+    let mut hasher = DefaultHasher::new();
+    nodes.hash(&mut hasher);
+    base_degree.hash(&mut hasher);
+    expansion_degree.hash(&mut hasher);
+    seed.hash(&mut hasher);
+    api_version_tag(api_version).hash(&mut hasher);
+    PathBuf::from(format!("r2-parents-{:016x}.cache", hasher.finish()))
+}
+
+/// A memory-mapped, validated binary cache of base/DRG parent rows.
+///
+/// Replaces the old approach of parsing an entire `g.json` into memory:
+/// the header is checked against the graph's actual parameters before
+/// it's trusted, and rows are read straight out of the mapped region
+/// rather than through a giant deserialized `Vec<Vec<usize>>`.
+struct ParentsCacheFile {
+    mmap: Mmap,
+}
+
+impl ParentsCacheFile {
+    fn header_matches(
+        bytes: &[u8],
+        nodes: usize,
+        base_degree: usize,
+        expansion_degree: usize,
+        seed: &[u32; 7],
+        api_version: ApiVersion,
+    ) -> bool {
+        if bytes.len() < CACHE_HEADER_BYTES {
+            return false;
+        }
+        let magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let file_nodes = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let file_base_degree = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+        let file_expansion_degree = u32::from_le_bytes(bytes[20..24].try_into().unwrap());
+        let mut file_seed = [0u32; 7];
+        for (slot, chunk) in file_seed.iter_mut().zip(bytes[24..52].chunks_exact(4)) {
+            *slot = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        let file_api_version = u32::from_le_bytes(bytes[52..CACHE_HEADER_BYTES].try_into().unwrap());
+
+        // A crash or a killed `new_cached` generation run can leave a
+        // file that's shorter than its header promises (writes there
+        // aren't atomic-renamed or length-verified); catch that here
+        // rather than letting `row` index past the mapped region later.
+        let expected_len = CACHE_HEADER_BYTES + nodes * CACHE_ROW_BYTES;
+
+        magic == CACHE_MAGIC
+            && version == CACHE_FORMAT_VERSION
+            && file_nodes == nodes as u64
+            && file_base_degree == base_degree as u32
+            && file_expansion_degree == expansion_degree as u32
+            && file_seed == *seed
+            && file_api_version == api_version_tag(api_version)
+            && bytes.len() == expected_len
+    }
+
+    fn write_header(
+        f: &mut File,
+        nodes: usize,
+        base_degree: usize,
+        expansion_degree: usize,
+        seed: &[u32; 7],
+        api_version: ApiVersion,
+    ) -> std::io::Result<()> {
+        f.write_all(&CACHE_MAGIC.to_le_bytes())?;
+        f.write_all(&CACHE_FORMAT_VERSION.to_le_bytes())?;
+        f.write_all(&(nodes as u64).to_le_bytes())?;
+        f.write_all(&(base_degree as u32).to_le_bytes())?;
+        f.write_all(&(expansion_degree as u32).to_le_bytes())?;
+        for word in seed {
+            f.write_all(&word.to_le_bytes())?;
+        }
+        f.write_all(&api_version_tag(api_version).to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read row `node` straight out of the mapped region into a
+    /// stack-allocated array -- no heap allocation, unlike building a
+    /// fresh `Vec` per call (which would just be trading the old
+    /// `Vec<Vec<usize>>` cache's problem for a per-read one).
+    fn row(&self, node: usize) -> [usize; BASE_PARENTS_PER_NODE] {
+        let offset = CACHE_HEADER_BYTES + node * CACHE_ROW_BYTES;
+        let bytes = &self.mmap[offset..offset + CACHE_ROW_BYTES];
+        let mut row = [0usize; BASE_PARENTS_PER_NODE];
+        for (slot, chunk) in row.iter_mut().zip(bytes.chunks_exact(4)) {
+            *slot = u32::from_le_bytes(chunk.try_into().unwrap()) as usize;
+        }
+        row
+    }
+}
+
+/// Base/DRG parent rows for every node: either held in memory (graphs
+/// built with `Graph::new`) or backed by a validated on-disk cache file
+/// (graphs built with `Graph::new_cached`).
+enum BaseParents {
+    Owned(Vec<[usize; BASE_PARENTS_PER_NODE]>),
+    Mapped(ParentsCacheFile),
+}
+
+impl BaseParents {
+    fn row(&self, node: usize) -> [usize; BASE_PARENTS_PER_NODE] {
+        match self {
+            BaseParents::Owned(rows) => rows[node],
+            BaseParents::Mapped(cache) => cache.row(node),
+        }
+    }
+}
+
 /// A Graph holds settings and cache
-#[derive(Serialize, Deserialize)]
 pub struct Graph {
     pub nodes: usize,
     base_degree: usize,
     expansion_degree: usize,
     seed: [u32; 7],
-    bas: Vec<Vec<usize>>,
-    exp: Vec<Vec<usize>>,
-    exp_reversed: Vec<Vec<usize>>,
+    /// Feistel permutation keys for the expander graph, derived from this
+    /// graph's `porep_id` (see `derive_feistel_keys`) rather than shared
+    /// across every graph.
+    feistel_keys: [u32; 4],
+    api_version: ApiVersion,
+    bas: BaseParents,
+    /// Node-entry count for `expander_state`'s caches, derived once from
+    /// the constructor's MiB budget.
+    expander_cache_capacity: usize,
+    expander_state: Mutex<ExpanderState>,
 }
 
 /// Given a node and a graph, find the parents of a node DRG graph
-fn bucketsample_parents(g: &Graph, node: usize) -> Vec<usize> {
+fn bucketsample_parents(g: &Graph, node: usize) -> [usize; BASE_PARENTS_PER_NODE] {
     let m = g.base_degree;
-    let mut parents = [0; 5];
+    let mut parents = [0; BASE_PARENTS_PER_NODE];
 
     match node {
         // Special case for the first node, it self references.
@@ -64,10 +328,54 @@ fn bucketsample_parents(g: &Graph, node: usize) -> Vec<usize> {
             // Use the degree of the curren graph (`m`), as parents.len() might be bigger
             // than that (that's the case for ZigZag Graph)
             parents[0..m].sort_unstable();
+
+            if g.api_version == ApiVersion::V1_1 {
+                // Guarantee `node - 1` is a base parent, and raise the rest
+                // to at least `MIN_BASE_PARENT_NODE` with no duplicates, so the
+                // `m` base slots never collide with each other.
+                parents[0] = node - 1;
+
+                // Below `MIN_BASE_PARENT_NODE + m`, there aren't `m - 1`
+                // distinct values left above `MIN_BASE_PARENT_NODE` (and
+                // below `node - 1`) to de-dup against, so widen the
+                // search down to 0. Either way, a monotonic "just
+                // increment" search can still collide with the newly
+                // forced `parents[0] = node - 1` and have nowhere left to
+                // go (e.g. an originally-sampled value one step below
+                // `node - 1`, scanning up, lands *on* `node - 1` with no
+                // room to continue). Track what's actually taken and wrap
+                // back around the `[floor, node - 2]` range instead of
+                // only ever counting up, so any value with a free slot
+                // anywhere in range finds it. The *only* node count for
+                // which distinctness is truly unreachable even then is
+                // `node < m` (fewer than `m` nodes exist before `node` at
+                // all); `Graph::parents` knows to skip the distinctness
+                // invariant for that case.
+                let floor = if node >= MIN_BASE_PARENT_NODE + m {
+                    MIN_BASE_PARENT_NODE
+                } else {
+                    0
+                };
+                let top = node - 2;
+                let range_len = (top + 1).saturating_sub(floor).max(1);
+                let mut used: std::collections::HashSet<usize> = std::collections::HashSet::new();
+                used.insert(parents[0]);
+                for k in 1..m {
+                    let mut candidate = cmp::max(parents[k], floor);
+                    let mut tries = 0;
+                    while used.contains(&candidate) && tries < range_len {
+                        candidate = if candidate >= top { floor } else { candidate + 1 };
+                        tries += 1;
+                    }
+                    parents[k] = candidate;
+                    used.insert(candidate);
+                }
+                parents[0..m].sort_unstable();
+            }
         }
     }
 
-    parents.to_vec()
+    parents
 }
 
 /// Given a node and a graph (and feistel settings) generate the expander
@@ -77,9 +385,6 @@ fn expander_parents(
     node: usize,
     feistel_precomputed: feistel::FeistelPrecomputed,
 ) -> Vec<usize> {
-    // Set the Feistel permutation keys
-    let feistel_keys = &[1, 2, 3, 4];
-
     // The expander graph parents are calculated by computing 3 rounds of the
     // feistel permutation on the current node
     let parents: Vec<usize> = (0..g.expansion_degree)
@@ -87,7 +392,7 @@ fn expander_parents(
             let parent = feistel::invert_permute(
                 (g.nodes * g.expansion_degree) as feistel::Index,
                 (node * g.expansion_degree + i) as feistel::Index,
-                feistel_keys,
+                &g.feistel_keys,
                 feistel_precomputed,
             ) as usize
                 / g.expansion_degree;
@@ -101,60 +406,182 @@ fn expander_parents(
     parents
 }
 
+/// The reverse of `expander_parents`: the nodes that have `node` as one
+/// of *their* expander parents (i.e. what `exp_reversed` used to
+/// materialize up front for every node at once). `permute` and
+/// `invert_permute` are exact inverses of each other, so running the
+/// permutation forward instead of inverted turns a parent lookup into a
+/// child lookup with no extra state.
+fn expander_children(
+    g: &Graph,
+    node: usize,
+    feistel_precomputed: feistel::FeistelPrecomputed,
+) -> Vec<usize> {
+    (0..g.expansion_degree)
+        .filter_map(|i| {
+            let child = feistel::permute(
+                (g.nodes * g.expansion_degree) as feistel::Index,
+                (node * g.expansion_degree + i) as feistel::Index,
+                &g.feistel_keys,
+                feistel_precomputed,
+            ) as usize
+                / g.expansion_degree;
+            if child > node {
+                Some(child)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
 impl Graph {
-    /// Create a graph
-    pub fn new(nodes: usize, base_degree: usize, expansion_degree: usize, seed: [u32; 7]) -> Self {
+    /// Assemble a `Graph` from its settings, deriving `seed`/`feistel_keys`
+    /// from `porep_id`. Shared by `new` and `new_cached`'s several return
+    /// points so the struct literal only has to be written once.
+    fn assemble(
+        nodes: usize,
+        base_degree: usize,
+        expansion_degree: usize,
+        porep_id: &[u8],
+        api_version: ApiVersion,
+        bas: BaseParents,
+        expander_cache_mib: usize,
+    ) -> Self {
         Graph {
             nodes,
             base_degree,
             expansion_degree,
-            seed,
-            exp: vec![vec![]; nodes],
-            bas: vec![vec![]; nodes],
-            exp_reversed: vec![vec![]; nodes],
+            seed: derive_drg_seed(porep_id),
+            feistel_keys: derive_feistel_keys(porep_id),
+            api_version,
+            bas,
+            expander_cache_capacity: expander_cache_capacity(expansion_degree, expander_cache_mib),
+            expander_state: Mutex::new(ExpanderState::default()),
         }
     }
-    // Create a graph, generate its parents and cache them.
-    // Parents are cached in a JSON file
+
+    /// Create a graph. `expander_cache_mib` bounds the memory used by the
+    /// on-demand expander parent/child caches (see `ExpanderCache`); `seed`
+    /// and the expander graph's Feistel keys are derived from `porep_id`
+    /// (see `derive_drg_seed`/`derive_feistel_keys`) rather than passed in
+    /// directly, so they're bound to the sector/prover they're replicating.
+    pub fn new(
+        nodes: usize,
+        base_degree: usize,
+        expansion_degree: usize,
+        porep_id: &[u8],
+        api_version: ApiVersion,
+        expander_cache_mib: usize,
+    ) -> Self {
+        Graph::assemble(
+            nodes,
+            base_degree,
+            expansion_degree,
+            porep_id,
+            api_version,
+            BaseParents::Owned(vec![[0usize; BASE_PARENTS_PER_NODE]; nodes]),
+            expander_cache_mib,
+        )
+    }
+
+    // Create a graph, generate its base/DRG parents and cache them in a
+    // memory-mapped binary file keyed by the graph's parameters.
     pub fn new_cached(
         nodes: usize,
         base_degree: usize,
-        expander_parents: usize,
-        seed: [u32; 7],
+        expansion_degree: usize,
+        porep_id: &[u8],
+        api_version: ApiVersion,
+        expander_cache_mib: usize,
     ) -> Graph {
-        if let Err(_) = metadata("g.json") {
-            println!("Parents not cached, creating them");
-            let mut gg = Graph::new(nodes, base_degree, expander_parents, seed);
-            gg.gen_parents_cache();
-            let mut f = File::create("g.json").expect("Unable to create file");
-            let j = serde_json::to_string(&gg).expect("unable to create json");
-            write!(f, "{}\n", j).expect("Unable to write file");
+        let seed = derive_drg_seed(porep_id);
+        let path = cache_file_path(nodes, base_degree, expansion_degree, &seed, api_version);
 
-            gg
+        if let Ok(file) = File::open(&path) {
+            let mmap = unsafe { Mmap::map(&file) }.expect("unable to mmap parents cache");
+            if ParentsCacheFile::header_matches(
+                &mmap,
+                nodes,
+                base_degree,
+                expansion_degree,
+                &seed,
+                api_version,
+            ) {
+                println!("Parents are cached, mapping {}", path.display());
+                return Graph::assemble(
+                    nodes,
+                    base_degree,
+                    expansion_degree,
+                    porep_id,
+                    api_version,
+                    BaseParents::Mapped(ParentsCacheFile { mmap }),
+                    expander_cache_mib,
+                );
+            }
+            println!(
+                "Cached parents at {} don't match this graph's parameters, regenerating",
+                path.display()
+            );
         } else {
-            println!("Parents are cached, loading them");
-            let mut f = File::open("g.json").expect("Unable to open the file");
-            let mut json = String::new();
-            f.read_to_string(&mut json)
-                .expect("Unable to read the file");
-            let gg = serde_json::from_str::<Graph>(&json).expect("unable to parse json");
-            gg
+            println!("Parents not cached, creating them");
+        }
+
+        let mut gg = Graph::new(
+            nodes,
+            base_degree,
+            expansion_degree,
+            porep_id,
+            api_version,
+            expander_cache_mib,
+        );
+        gg.gen_parents_cache();
+
+        {
+            let mut f = File::create(&path).expect("unable to create parents cache file");
+            ParentsCacheFile::write_header(
+                &mut f,
+                nodes,
+                base_degree,
+                expansion_degree,
+                &seed,
+                api_version,
+            )
+            .expect("unable to write parents cache header");
+            if let BaseParents::Owned(rows) = &gg.bas {
+                for row in rows {
+                    for parent in row.iter() {
+                        f.write_all(&(*parent as u32).to_le_bytes())
+                            .expect("unable to write parents cache row");
+                    }
+                }
+            }
         }
+
+        let file = File::open(&path).expect("unable to reopen parents cache file");
+        let mmap = unsafe { Mmap::map(&file) }.expect("unable to mmap parents cache");
+        gg.bas = BaseParents::Mapped(ParentsCacheFile { mmap });
+        gg
     }
 
     /// Load the parents of a node from cache
     pub fn parents(&self, node: usize, layer: usize, parents: &mut [usize]) {
         let mut ps = vec![];
 
+        // The node `bucketsample_parents` actually sampled against: `node`
+        // itself on an even layer, or its mirror on an odd one. Used below
+        // to know whether the V1_1 distinctness invariant even applies.
+        let sampled_node = if layer % 2 == 0 { node } else { self.nodes - node - 1 };
+
         let base_parents = {
             if layer % 2 == 0 {
-                self.bas[node].clone()
+                self.bas.row(node).to_vec()
             } else {
                 // On an odd layer, invert the graph:
                 // - given a node n, find the parents of nodes - n - 1
                 // - for each parent, return nodes - parent - 1
-                let n = self.nodes - node - 1;
-                self.bas[n]
+                self.bas
+                    .row(sampled_node)
                     .iter()
                     .map(|x| self.nodes - x - 1)
                     .collect::<Vec<usize>>()
@@ -163,14 +590,25 @@ impl Graph {
 
         let exp_parents = {
             if layer % 2 == 0 {
-                self.exp[node].clone()
+                self.expander_parents(node)
             } else {
                 // On an odd layer, reverse the edges:
                 // A->B is now B->A
-                self.exp_reversed[node].clone()
+                self.expander_children(node)
             }
         };
 
+        // Below `base_degree`, there aren't even `base_degree` distinct
+        // nodes before `sampled_node` to draw from, so distinctness is
+        // unreachable regardless of how `bucketsample_parents` searches;
+        // see the dedup loop there for the rest of this reasoning.
+        if self.api_version == ApiVersion::V1_1 && sampled_node >= self.base_degree {
+            debug_assert!(
+                base_parents.iter().collect::<std::collections::HashSet<_>>().len() == base_parents.len(),
+                "ApiVersion::V1_1 base parents must be distinct"
+            );
+        }
+
         // Pad the parents, the size of the total parents must be `PARENTS_SIZE`
         ps.extend(pad_parents(base_parents, self.base_degree));
         ps.extend(pad_parents(exp_parents, self.expansion_degree));
@@ -181,23 +619,42 @@ impl Graph {
         }
     }
 
-    pub fn gen_parents_cache(&mut self) {
-        let fp = feistel::precompute((self.expansion_degree * self.nodes) as feistel::Index);
+    /// Expander parents of `node`, computed on a cache miss and memoized
+    /// in the bounded `ExpanderCache`.
+    fn expander_parents(&self, node: usize) -> Vec<usize> {
+        let mut state = self.expander_state.lock().unwrap();
+        let fp = *state
+            .feistel_precomputed
+            .get_or_insert_with(|| feistel::precompute((self.expansion_degree * self.nodes) as feistel::Index));
+        state.parents.ensure_capacity(self.expander_cache_capacity);
+        state
+            .parents
+            .get_or_insert_with(node, || expander_parents(self, node, fp))
+    }
 
-        // Cache only forward DRG and Expander parents
-        for node in 0..self.nodes {
-            self.bas[node] = bucketsample_parents(&self, node);
-            self.exp[node] = expander_parents(&self, node, fp);
-        }
+    /// Nodes that have `node` as an expander parent, computed on a cache
+    /// miss and memoized in the bounded `ExpanderCache`.
+    fn expander_children(&self, node: usize) -> Vec<usize> {
+        let mut state = self.expander_state.lock().unwrap();
+        let fp = *state
+            .feistel_precomputed
+            .get_or_insert_with(|| feistel::precompute((self.expansion_degree * self.nodes) as feistel::Index));
+        state.children.ensure_capacity(self.expander_cache_capacity);
+        state
+            .children
+            .get_or_insert_with(node, || expander_children(self, node, fp))
+    }
 
-        // Cache reverse edges for exp
-        for (n1, v) in self.exp.iter().enumerate() {
-            for n2 in v {
-                self.exp_reversed[*n2].push(n1);
-            }
+    pub fn gen_parents_cache(&mut self) {
+        // Only the base/DRG parents are worth precomputing up front: they
+        // don't follow the monotonic access pattern `parents()` uses for
+        // expander edges, so there's no small window to cache them in.
+        if let BaseParents::Owned(_) = &self.bas {
+            let rows = (0..self.nodes)
+                .map(|node| bucketsample_parents(&self, node))
+                .collect();
+            self.bas = BaseParents::Owned(rows);
         }
-
-        // TODO: sort parents
     }
 
     pub fn degree(&self) -> usize {
@@ -212,3 +669,119 @@ fn pad_parents(mut v: Vec<usize>, size: usize) -> Vec<usize> {
     }
     v
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn graph_for_dedup_test() -> Graph {
+        Graph::new(64, 5, 8, b"test porep id", ApiVersion::V1_1, 0)
+    }
+
+    /// Every node at or above `base_degree` must get `base_degree`
+    /// pairwise-distinct base parents under `ApiVersion::V1_1`, including
+    /// the handful of nodes right at the `MIN_BASE_PARENT_NODE` boundary
+    /// where the dedup search used to bail out early and leave a
+    /// duplicate in place, and nodes where an originally-sampled value
+    /// collides with the newly forced `parents[0] = node - 1` with
+    /// nowhere left to go (a monotonic "just increment" search used to
+    /// give up on those rather than wrapping to find a free slot).
+    /// Swept across several `porep_id`s since any single seed only
+    /// happens to exercise a handful of the colliding cases.
+    #[test]
+    fn v1_1_base_parents_are_distinct_once_enough_nodes_exist() {
+        for porep_id in &[
+            &b"test porep id"[..],
+            &b"another porep id"[..],
+            &b"yet another one"[..],
+            &b""[..],
+        ] {
+            let g = Graph::new(512, 5, 8, porep_id, ApiVersion::V1_1, 0);
+            for node in g.base_degree..g.nodes {
+                let parents = bucketsample_parents(&g, node);
+                let distinct: HashSet<_> = parents.iter().collect();
+                assert_eq!(
+                    distinct.len(),
+                    parents.len(),
+                    "node {} has duplicate base parents: {:?} (porep_id {:?})",
+                    node,
+                    parents,
+                    porep_id
+                );
+            }
+        }
+    }
+
+    /// `node - 1` must always be a base parent under `ApiVersion::V1_1`,
+    /// for every node the dedup logic runs on (it's hardcoded for the
+    /// `0 | 1` special case).
+    #[test]
+    fn v1_1_always_includes_node_minus_one() {
+        let g = graph_for_dedup_test();
+        for node in 2..g.nodes {
+            let parents = bucketsample_parents(&g, node);
+            assert!(
+                parents.contains(&(node - 1)),
+                "node {} is missing its node - 1 base parent: {:?}",
+                node,
+                parents
+            );
+        }
+    }
+
+    fn cache_header_bytes(nodes: usize, seed: &[u32; 7], api_version: ApiVersion) -> Vec<u8> {
+        let mut f = Vec::new();
+        f.extend_from_slice(&CACHE_MAGIC.to_le_bytes());
+        f.extend_from_slice(&CACHE_FORMAT_VERSION.to_le_bytes());
+        f.extend_from_slice(&(nodes as u64).to_le_bytes());
+        f.extend_from_slice(&(5u32).to_le_bytes());
+        f.extend_from_slice(&(8u32).to_le_bytes());
+        for word in seed {
+            f.extend_from_slice(&word.to_le_bytes());
+        }
+        f.extend_from_slice(&api_version_tag(api_version).to_le_bytes());
+        f
+    }
+
+    /// A header matching every parameter, with exactly the right number
+    /// of trailing row bytes, must validate.
+    #[test]
+    fn cache_header_matches_on_exact_length() {
+        let seed = [1, 2, 3, 4, 5, 6, 7];
+        let nodes = 10;
+        let mut bytes = cache_header_bytes(nodes, &seed, ApiVersion::V1);
+        bytes.extend(vec![0u8; nodes * CACHE_ROW_BYTES]);
+        assert!(ParentsCacheFile::header_matches(&bytes, nodes, 5, 8, &seed, ApiVersion::V1));
+    }
+
+    /// A cache file truncated by a crash or a killed generation run --
+    /// same header, but missing some of its row bytes -- must be
+    /// rejected rather than accepted and later indexed out of bounds by
+    /// `row`.
+    #[test]
+    fn cache_header_rejects_truncated_file() {
+        let seed = [1, 2, 3, 4, 5, 6, 7];
+        let nodes = 10;
+        let mut bytes = cache_header_bytes(nodes, &seed, ApiVersion::V1);
+        // Only write back half the rows, as a crash mid-write would.
+        bytes.extend(vec![0u8; (nodes / 2) * CACHE_ROW_BYTES]);
+        assert!(!ParentsCacheFile::header_matches(&bytes, nodes, 5, 8, &seed, ApiVersion::V1));
+    }
+
+    /// A cache generated for different graph parameters must be
+    /// rejected so the caller regenerates it instead of misreading rows
+    /// sampled for a different `nodes`/`seed`/`api_version`.
+    #[test]
+    fn cache_header_rejects_parameter_mismatch() {
+        let seed = [1, 2, 3, 4, 5, 6, 7];
+        let nodes = 10;
+        let mut bytes = cache_header_bytes(nodes, &seed, ApiVersion::V1);
+        bytes.extend(vec![0u8; nodes * CACHE_ROW_BYTES]);
+
+        assert!(!ParentsCacheFile::header_matches(&bytes, nodes + 1, 5, 8, &seed, ApiVersion::V1));
+        assert!(!ParentsCacheFile::header_matches(&bytes, nodes, 5, 8, &seed, ApiVersion::V1_1));
+        let other_seed = [7, 6, 5, 4, 3, 2, 1];
+        assert!(!ParentsCacheFile::header_matches(&bytes, nodes, 5, 8, &other_seed, ApiVersion::V1));
+    }
+}
@@ -45,15 +45,70 @@ fn stop_profile() {}
 use crate::graph::{Graph, Parents, ParentsIter, ParentsIterRev};
 use crate::{next_base, next_base_rev, next_exp, AsyncData, BASE_PARENTS, NODES, NODE_SIZE};
 
+mod parallel;
+
+/// Producer threads pinned alongside the consumer in each layer's labeling
+/// pipeline. Chosen to leave the consumer's own core free while still
+/// fitting in a typical 4-core L2/L3 sharing group.
+const PRODUCER_CORES: usize = 3;
+/// How many nodes producers are allowed to run ahead of the consumer.
+const PIPELINE_LOOKAHEAD: usize = 8;
+
+/// A read-only snapshot of every node's data as it stood before the
+/// current layer's pass started.
+///
+/// ZigZag's expander parents reach across layers: layer `l`'s expander
+/// reads must see layer `l - 1`'s finished output (or the original,
+/// unencoded data for layer 0), not whatever layer `l` has encoded so
+/// far. Base parents, in contrast, only ever reference earlier nodes
+/// *within* the layer being encoded, which `create_key`/`create_key_rev`
+/// can read straight out of `data` since those nodes are already
+/// overwritten in processing order. Capturing the prior layer up front
+/// (rather than threading two live `AsyncData` buffers through) also
+/// means the bulk of each node's parents -- its expander parents -- have
+/// no same-layer write-after-read dependency, which is what makes the
+/// producer pipeline in `parallel::run_pipelined` actually able to run
+/// ahead of the consumer.
+///
+/// Held as one contiguous allocation rather than a `Vec<u8>` per node:
+/// `ExpanderCache`/`ParentsCacheFile` already moved the parents caches
+/// away from a `Vec`-per-entry layout for exactly this reason (per-entry
+/// heap overhead at billions of nodes), and the same lesson applies here
+/// for the much larger node-data buffer. A true zero-copy version of
+/// this -- reading layer `l - 1`'s output straight out of a second live
+/// `AsyncData` buffer instead of copying it at all -- needs a
+/// dual-buffer or layer-offset scheme inside `AsyncData` itself; that's
+/// out of scope for this module until `AsyncData` exposes it.
+struct PriorLayer {
+    nodes: Vec<u8>,
+}
+
+impl PriorLayer {
+    fn capture(data: &AsyncData) -> Self {
+        let mut nodes = Vec::with_capacity(NODES * NODE_SIZE);
+        for node in 0..NODES {
+            nodes.extend_from_slice(&data.get_node(node));
+        }
+        PriorLayer { nodes }
+    }
+
+    fn get_node(&self, node: usize) -> &[u8] {
+        &self.nodes[node * NODE_SIZE..(node + 1) * NODE_SIZE]
+    }
+}
+
 macro_rules! replicate_layer {
     ($graph:expr, $replica_id:expr, $layer:expr, $data:expr) => {
         print!("Replicating layer {}", $layer);
         let start = Instant::now();
 
+        // `replica_id` and the layer index are the same for every node in
+        // this layer, so absorb them once here; per-node hashing only has
+        // to clone this midstate and feed in the node index and parents.
         let mut hasher = Blake2s::new().hash_length(NODE_SIZE).to_state();
         hasher.update($replica_id.as_ref());
+        hasher.update(&($layer as u64).to_le_bytes());
 
-        let mut key_dur = Duration::new(0, 0);
         let mut write_time = Duration::new(0, 0);
 
         // prefetch first node
@@ -62,41 +117,45 @@ macro_rules! replicate_layer {
         $data.prefetch(2, false);
         $data.prefetch(3, false);
 
-        for node in 0..NODES {
-            // println!("--round {}", node);
-
-            // prefetch next node
-            if node < NODES - 4 {
-                $data.prefetch(node + 4, false);
-            }
-
-            let parents = ParentsIter::new($graph.clone(), node);
-
-            let start = Instant::now();
-            // println!("-- key {}", node);
-            // Compute `key` from `parents`
-            let key = create_key::<H>(&parents, node, $data, hasher.clone());
-            key_dur += start.elapsed();
-
-            // println!("-- raw node {}", node);
-            // Get the `unencoded` node
-            let mut raw_node_data = $data.get_node(node);
-            let node_data = H::Domain::try_from_bytes(&raw_node_data).unwrap();
-            let mut node_fr: Fr = node_data.into();
-
-            // Compute the `encoded` node by adding the `key` to it
-            node_fr.add_assign(&key.into());
-            let encoded: H::Domain = node_fr.into();
-
-            let start = Instant::now();
-            // Store the `encoded` data
-            encoded.write_bytes(&mut raw_node_data).unwrap();
-            $data.write_node(node, raw_node_data);
-            write_time += start.elapsed();
-        }
+        // Snapshot the layer below before this layer overwrites `$data` in
+        // place; expander parents read from this, not from `$data`.
+        let prior_layer = PriorLayer::capture(&$data);
+
+        let core_group = parallel::checkout_core_group(PRODUCER_CORES);
+        let graph = $graph.clone();
+        let data = &*$data;
+        let hasher_template = hasher.clone();
+
+        parallel::run_pipelined(
+            NODES,
+            &core_group,
+            PIPELINE_LOOKAHEAD,
+            |node, barrier| {
+                if node < NODES - 4 {
+                    data.prefetch(node + 4, false);
+                }
+                let parents = ParentsIter::new(graph.clone(), node);
+                create_key::<H>(&parents, node, data, &prior_layer, barrier, hasher_template.clone())
+            },
+            |node, key| {
+                // Get the `unencoded` node
+                let mut raw_node_data = $data.get_node(node);
+                let node_data = H::Domain::try_from_bytes(&raw_node_data).unwrap();
+                let mut node_fr: Fr = node_data.into();
+
+                // Compute the `encoded` node by adding the `key` to it
+                node_fr.add_assign(&key.into());
+                let encoded: H::Domain = node_fr.into();
+
+                let w_start = Instant::now();
+                // Store the `encoded` data
+                encoded.write_bytes(&mut raw_node_data).unwrap();
+                $data.write_node(node, raw_node_data);
+                write_time += w_start.elapsed();
+            },
+        );
 
         println!(" ... took {:0.4}ms", start.elapsed().as_millis());
-        println!("  key: {:0.4}ms", key_dur.as_millis());
         println!("  write: {:0.4}ms", write_time.as_millis());
     };
 }
@@ -106,109 +165,170 @@ macro_rules! replicate_layer_rev {
         print!("Replicating layer {}", $layer);
         let start = Instant::now();
 
+        // `replica_id` and the layer index are the same for every node in
+        // this layer, so absorb them once here; per-node hashing only has
+        // to clone this midstate and feed in the node index and parents.
         let mut hasher = Blake2s::new().hash_length(NODE_SIZE).to_state();
         hasher.update($replica_id.as_ref());
+        hasher.update(&($layer as u64).to_le_bytes());
 
         // prefetch first node
         $data.prefetch(0, true);
         $data.prefetch(1, true);
 
-        for node in 0..NODES {
-            // prefetch next node
-            if node < NODES - 2 {
-                $data.prefetch(node + 2, true);
-            }
-
-            let parents = ParentsIterRev::new($graph.clone(), node);
-
-            // Compute `key` from `parents`
-            // TODO: use rev again
-            let key = create_key_rev::<H>(&parents, node, $data, hasher.clone());
-
-            // Get the `unencoded` node
-            let mut raw_node_data = $data.get_node(node);
-            let node_data = H::Domain::try_from_bytes(&raw_node_data).unwrap();
-            let mut node_fr: Fr = node_data.into();
-
-            // Compute the `encoded` node by adding the `key` to it
-            node_fr.add_assign(&key.into());
-            let encoded: H::Domain = node_fr.into();
-
-            // Store the `encoded` data
-            encoded.write_bytes(&mut raw_node_data).unwrap();
-            $data.write_node(node, raw_node_data);
-        }
+        // Snapshot the layer below before this layer overwrites `$data` in
+        // place; expander parents read from this, not from `$data`.
+        let prior_layer = PriorLayer::capture(&$data);
+
+        let core_group = parallel::checkout_core_group(PRODUCER_CORES);
+        let graph = $graph.clone();
+        let data = &*$data;
+        let hasher_template = hasher.clone();
+
+        parallel::run_pipelined(
+            NODES,
+            &core_group,
+            PIPELINE_LOOKAHEAD,
+            |node, barrier| {
+                if node < NODES - 2 {
+                    data.prefetch(node + 2, true);
+                }
+                let parents = ParentsIterRev::new(graph.clone(), node);
+                create_key_rev::<H>(&parents, node, data, &prior_layer, barrier, hasher_template.clone())
+            },
+            |node, key| {
+                // Get the `unencoded` node
+                let mut raw_node_data = $data.get_node(node);
+                let node_data = H::Domain::try_from_bytes(&raw_node_data).unwrap();
+                let mut node_fr: Fr = node_data.into();
+
+                // Compute the `encoded` node by adding the `key` to it
+                node_fr.add_assign(&key.into());
+                let encoded: H::Domain = node_fr.into();
+
+                // Store the `encoded` data
+                encoded.write_bytes(&mut raw_node_data).unwrap();
+                $data.write_node(node, raw_node_data);
+            },
+        );
 
         println!(" ... took {:0.4}ms", start.elapsed().as_millis());
     };
 }
 
-/// Generates a ZigZag replicated sector.
+/// Generates a ZigZag replicated sector, returning `CommR`: a commitment
+/// to the final (10th) layer's encoded data.
 #[inline(never)]
 pub fn r2<H>(
     replica_id: H::Domain,
     data: &mut AsyncData,
     g: Arc<Graph>,
-) -> Result<(), failure::Error>
+) -> Result<H::Domain, failure::Error>
 where
     H: Hasher,
 {
     start_profile("replicate");
 
-    // Generate a replica at each layer of the 10 layers
+    // Generate a replica at each of the 10 layers, alternating forward
+    // and reverse (odd layers flip base-parent indices and use the
+    // reversed expander edges, `expander_children`, via `Graph::parents`).
     replicate_layer!(g, replica_id, 0, data);
-    // replicate_layer_rev!(g, replica_id, 1, data);
+    replicate_layer_rev!(g, replica_id, 1, data);
 
-    // replicate_layer!(g, replica_id, 2, data);
-    // replicate_layer_rev!(g, replica_id, 3, data);
+    replicate_layer!(g, replica_id, 2, data);
+    replicate_layer_rev!(g, replica_id, 3, data);
 
-    // replicate_layer!(g, replica_id, 4, data);
-    // replicate_layer_rev!(g, replica_id, 5, data);
+    replicate_layer!(g, replica_id, 4, data);
+    replicate_layer_rev!(g, replica_id, 5, data);
 
-    // replicate_layer!(g, replica_id, 6, data);
-    // replicate_layer_rev!(g, replica_id, 7, data);
+    replicate_layer!(g, replica_id, 6, data);
+    replicate_layer_rev!(g, replica_id, 7, data);
 
-    // replicate_layer!(g, replica_id, 8, data);
-    // replicate_layer_rev!(g, replica_id, 9, data);
+    replicate_layer!(g, replica_id, 8, data);
+    replicate_layer_rev!(g, replica_id, 9, data);
 
     stop_profile();
 
-    Ok(())
+    Ok(commit_last_layer::<H>(data))
 }
 
-macro_rules! hash {
-    ($parent:expr, $hasher:expr, $data:expr) => {
+/// `CommR`: a commitment to the fully-replicated sector, taken as a
+/// single Blake2s digest over every node of the last layer's encoded
+/// data.
+fn commit_last_layer<H: Hasher>(data: &AsyncData) -> H::Domain {
+    let mut hasher = Blake2s::new().hash_length(NODE_SIZE).to_state();
+    for node in 0..NODES {
+        hasher.update(&data.get_node(node));
+    }
+    let hash = hasher.finalize();
+    bytes_into_fr_repr_safe(hash.as_ref()).into()
+}
+
+/// Hash a base parent's data: base parents only ever reference earlier
+/// nodes within the layer being encoded, so they're read straight out of
+/// the live (in-progress) `$data` buffer. That buffer is shared with the
+/// pipeline's producer threads, which may run ahead of the consumer
+/// that performs the actual write-back (see `parallel::run_pipelined`),
+/// so the read has to wait on `$barrier` for this specific parent first.
+macro_rules! hash_base {
+    ($parent:expr, $hasher:expr, $data:expr, $barrier:expr) => {
+        $barrier.wait_for($parent);
         $hasher.update(&$data.get_node($parent));
     };
 }
 
+/// Hash an expander parent's data: expander parents reach across
+/// layers, so they're read out of `$prior`, the snapshot of the layer
+/// below (see `PriorLayer`).
+macro_rules! hash_exp {
+    ($parent:expr, $hasher:expr, $prior:expr) => {
+        $hasher.update($prior.get_node($parent));
+    };
+}
+
 fn create_key<'a, H: Hasher>(
     parents: &'a ParentsIter,
     node: usize,
-    data: &'a mut AsyncData,
+    data: &'a AsyncData,
+    prior: &'a PriorLayer,
+    barrier: &'a parallel::WriteBarrier,
     mut hasher: State,
 ) -> H::Domain {
     // compile time fixed at 5 + 8 = 13 parents
 
+    // Bind the label to this exact node: `hasher` only carries the
+    // per-layer prefix (replica_id || layer) so far, so two nodes with
+    // identical parent contents would otherwise hash identically.
+    hasher.update(&(node as u64).to_le_bytes());
+
     // The hash is about the parents, hence skip if a node doesn't have any parents
     let p0 = next_base!(parents, 0);
-    // if node != p0 {
     // base parents
-    hasher.update(&data.get_node(p0));
-    hash!(next_base!(parents, 1), hasher, data);
-    hash!(next_base!(parents, 2), hasher, data);
-    hash!(next_base!(parents, 3), hasher, data);
-    hash!(next_base!(parents, 4), hasher, data);
+    if p0 == node {
+        // `bucketsample_parents`'s `0 | 1` special case self-references:
+        // node 0's own first base parent is node 0. Reading that off the
+        // live buffer would mean waiting on this very node's write-back,
+        // which can't happen until this call returns -- a deadlock. Read
+        // the pre-layer value instead, exactly like an expander parent.
+        hash_exp!(p0, hasher, prior);
+    } else {
+        barrier.wait_for(p0);
+        hasher.update(&data.get_node(p0));
+    }
+    hash_base!(next_base!(parents, 1), hasher, data, barrier);
+    hash_base!(next_base!(parents, 2), hasher, data, barrier);
+    hash_base!(next_base!(parents, 3), hasher, data, barrier);
+    hash_base!(next_base!(parents, 4), hasher, data, barrier);
 
     // exp parents
-    hash!(next_exp!(parents, 5), hasher, data);
-    hash!(next_exp!(parents, 6), hasher, data);
-    hash!(next_exp!(parents, 7), hasher, data);
-    hash!(next_exp!(parents, 8), hasher, data);
-    hash!(next_exp!(parents, 9), hasher, data);
-    hash!(next_exp!(parents, 10), hasher, data);
-    hash!(next_exp!(parents, 11), hasher, data);
-    hash!(next_exp!(parents, 12), hasher, data);
+    hash_exp!(next_exp!(parents, 5), hasher, prior);
+    hash_exp!(next_exp!(parents, 6), hasher, prior);
+    hash_exp!(next_exp!(parents, 7), hasher, prior);
+    hash_exp!(next_exp!(parents, 8), hasher, prior);
+    hash_exp!(next_exp!(parents, 9), hasher, prior);
+    hash_exp!(next_exp!(parents, 10), hasher, prior);
+    hash_exp!(next_exp!(parents, 11), hasher, prior);
+    hash_exp!(next_exp!(parents, 12), hasher, prior);
     // }
 
     let hash = hasher.finalize();
@@ -218,34 +338,106 @@ fn create_key<'a, H: Hasher>(
 fn create_key_rev<'a, H: Hasher>(
     parents: &'a ParentsIterRev,
     node: usize,
-    data: &'a mut AsyncData,
+    data: &'a AsyncData,
+    prior: &'a PriorLayer,
+    barrier: &'a parallel::WriteBarrier,
     mut hasher: State,
 ) -> H::Domain {
     // compile time fixed at 5 + 8 = 13 parents
 
+    // Bind the label to this exact node; see `create_key`.
+    hasher.update(&(node as u64).to_le_bytes());
+
     // The hash is about the parents, hence skip if a node doesn't have any parents
     let p0 = next_base_rev!(parents, 0);
-    //  if node != p0 {
     // hash first parent
-    hasher.update(&data.get_node(p0));
+    if p0 == node {
+        // Same self-reference case as `create_key`: the odd-layer mirror
+        // of node 0's `0 | 1` self-reference recurs at `node == NODES - 1`
+        // on every reverse layer. Read the pre-layer value instead of
+        // waiting on this node's own write-back.
+        hash_exp!(p0, hasher, prior);
+    } else {
+        barrier.wait_for(p0);
+        hasher.update(&data.get_node(p0));
+    }
 
     // base parents
-    hash!(next_base_rev!(parents, 1), hasher, data);
-    hash!(next_base_rev!(parents, 2), hasher, data);
-    hash!(next_base_rev!(parents, 3), hasher, data);
-    hash!(next_base_rev!(parents, 4), hasher, data);
+    hash_base!(next_base_rev!(parents, 1), hasher, data, barrier);
+    hash_base!(next_base_rev!(parents, 2), hasher, data, barrier);
+    hash_base!(next_base_rev!(parents, 3), hasher, data, barrier);
+    hash_base!(next_base_rev!(parents, 4), hasher, data, barrier);
 
     // exp parents
-    hash!(next_exp!(parents, 5), hasher, data);
-    hash!(next_exp!(parents, 6), hasher, data);
-    hash!(next_exp!(parents, 7), hasher, data);
-    hash!(next_exp!(parents, 8), hasher, data);
-    hash!(next_exp!(parents, 9), hasher, data);
-    hash!(next_exp!(parents, 10), hasher, data);
-    hash!(next_exp!(parents, 11), hasher, data);
-    hash!(next_exp!(parents, 12), hasher, data);
+    hash_exp!(next_exp!(parents, 5), hasher, prior);
+    hash_exp!(next_exp!(parents, 6), hasher, prior);
+    hash_exp!(next_exp!(parents, 7), hasher, prior);
+    hash_exp!(next_exp!(parents, 8), hasher, prior);
+    hash_exp!(next_exp!(parents, 9), hasher, prior);
+    hash_exp!(next_exp!(parents, 10), hasher, prior);
+    hash_exp!(next_exp!(parents, 11), hasher, prior);
+    hash_exp!(next_exp!(parents, 12), hasher, prior);
     // }
 
     let hash = hasher.finalize();
     bytes_into_fr_repr_safe(hash.as_ref()).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `create_key`/`create_key_rev` build their preimage by absorbing
+    /// `replica_id || layer` once into a template hasher, cloning that
+    /// midstate per node, then absorbing `node || parents...`. That has
+    /// to produce exactly the same digest as hashing
+    /// `replica_id || layer || node || parents...` in one pass, or the
+    /// per-layer midstate optimization would be silently corrupting the
+    /// label preimage.
+    #[test]
+    fn midstate_clone_matches_one_shot_preimage() {
+        let replica_id = b"some replica id long enough";
+        let layer: u64 = 3;
+        let node: u64 = 42;
+        let parent_data = b"fake parent bytes";
+
+        let mut template = Blake2s::new().hash_length(NODE_SIZE).to_state();
+        template.update(replica_id.as_ref());
+        template.update(&layer.to_le_bytes());
+
+        let mut via_clone = template.clone();
+        via_clone.update(&node.to_le_bytes());
+        via_clone.update(parent_data);
+        let via_clone_hash = via_clone.finalize();
+
+        let mut one_shot = Blake2s::new().hash_length(NODE_SIZE).to_state();
+        one_shot.update(replica_id.as_ref());
+        one_shot.update(&layer.to_le_bytes());
+        one_shot.update(&node.to_le_bytes());
+        one_shot.update(parent_data);
+        let one_shot_hash = one_shot.finalize();
+
+        assert_eq!(via_clone_hash.as_ref(), one_shot_hash.as_ref());
+    }
+
+    /// Two nodes with byte-for-byte identical parent data must still get
+    /// different labels: the node index has to actually enter the
+    /// preimage, not just the layer/replica_id prefix.
+    #[test]
+    fn distinct_node_index_changes_the_preimage() {
+        let replica_id = b"some replica id long enough";
+        let layer: u64 = 0;
+        let parent_data = b"identical parent bytes for both nodes";
+
+        let hash_for_node = |node: u64| {
+            let mut hasher = Blake2s::new().hash_length(NODE_SIZE).to_state();
+            hasher.update(replica_id.as_ref());
+            hasher.update(&layer.to_le_bytes());
+            hasher.update(&node.to_le_bytes());
+            hasher.update(parent_data);
+            hasher.finalize().as_ref().to_vec()
+        };
+
+        assert_ne!(hash_for_node(0), hash_for_node(1));
+    }
+}
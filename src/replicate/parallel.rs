@@ -0,0 +1,237 @@
+//! Core-pinned producer/consumer pipeline for layer labeling.
+//!
+//! Label `i` depends on label `i - 1` (plus base/expander parents that
+//! all lie earlier in the layer), so labeling can't be split across
+//! nodes the way an embarrassingly-parallel map would. What *can* be
+//! overlapped is the memory-bound part -- hashing each node's parents --
+//! with the strictly sequential finalize-and-write step. A handful of
+//! "producer" threads race a few nodes ahead of the consumer, hashing
+//! each node's parent data into a finished key in a ring buffer; the
+//! consumer drains it in order and writes each node back.
+//!
+//! Racing ahead is only safe for data a producer can read from an
+//! immutable snapshot (expander parents). Base parents are read straight
+//! out of the live, in-place buffer, and can reference a node only one
+//! or two positions behind -- so a producer must wait on a
+//! `WriteBarrier` for that specific node's write-back before reading it,
+//! not just stay within the ring's lookahead window.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crossbeam::thread as cb_thread;
+
+/// A single logical CPU core, as handed out by `checkout_core_group`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreIndex(pub usize);
+
+/// Pin the calling thread to `core` for the remainder of its life.
+///
+/// Producers and the consumer only benefit from overlap if the ring
+/// buffer handoff between them stays on cores sharing an L2/L3 cache --
+/// otherwise the cross-core traffic costs more than the serial path
+/// saves.
+pub fn bind_core(core: CoreIndex) -> Result<(), failure::Error> {
+    let ids = core_affinity::get_core_ids()
+        .ok_or_else(|| failure::err_msg("failed to enumerate CPU cores"))?;
+    let id = ids
+        .get(core.0)
+        .ok_or_else(|| failure::err_msg(format!("no core at index {}", core.0)))?;
+    core_affinity::set_for_current(*id);
+    Ok(())
+}
+
+/// Pick `count` sibling cores for a producer/consumer group.
+///
+/// Cores `0..count` are assumed, by convention on the target hardware,
+/// to be adjacent indices sharing an L2/L3 cache; this is where a real
+/// deployment would instead consult topology (e.g. `/sys/devices/system/cpu`)
+/// to pick true siblings.
+pub fn checkout_core_group(count: usize) -> Vec<CoreIndex> {
+    (0..count).map(CoreIndex).collect()
+}
+
+/// A fixed-size buffer whose slots are written from multiple threads
+/// without locking.
+///
+/// # Safety
+/// Callers must guarantee that any two threads never access the same
+/// index concurrently. `LabelRing`'s per-slot ready flags are what
+/// uphold that guarantee here.
+struct UnsafeSlice<T> {
+    cells: Box<[UnsafeCell<T>]>,
+}
+
+unsafe impl<T: Send> Sync for UnsafeSlice<T> {}
+
+impl<T> UnsafeSlice<T> {
+    fn new(len: usize, mut init: impl FnMut() -> T) -> Self {
+        UnsafeSlice {
+            cells: (0..len).map(|_| UnsafeCell::new(init())).collect(),
+        }
+    }
+
+    /// # Safety
+    /// The caller must have exclusive access to `index`.
+    unsafe fn replace(&self, index: usize, value: T) -> T {
+        std::mem::replace(&mut *self.cells[index].get(), value)
+    }
+}
+
+/// Tracks how far the consumer's write-back has actually progressed, so
+/// a producer racing ahead can tell when it's safe to read a given
+/// node's bytes out of the live (in-place) data buffer.
+///
+/// Base parents are sampled from nodes very close to (often exactly
+/// `node - 1`), so a producer several nodes ahead of the consumer would
+/// otherwise read stale, not-yet-re-encoded bytes for them. The coarse
+/// `consumed`/ring-capacity bookkeeping in `run_pipelined` only protects
+/// ring slots from being overwritten before they're drained -- it says
+/// nothing about when a given node's write-back actually lands in the
+/// live buffer, which is the thing a live read needs to wait on.
+pub struct WriteBarrier {
+    written_through: AtomicUsize,
+}
+
+impl WriteBarrier {
+    fn new() -> Self {
+        WriteBarrier {
+            written_through: AtomicUsize::new(0),
+        }
+    }
+
+    /// Block until `node`'s write-back has completed. Callers reading
+    /// `node`'s bytes out of the live data buffer (base parents) must
+    /// call this first; reads from a prior-layer snapshot never need to.
+    pub fn wait_for(&self, node: usize) {
+        while self.written_through.load(Ordering::Acquire) <= node {
+            std::thread::yield_now();
+        }
+    }
+
+    /// Record that `node`'s write-back has completed.
+    fn advance_past(&self, node: usize) {
+        self.written_through.store(node + 1, Ordering::Release);
+    }
+}
+
+/// Fixed-capacity ring buffer of pre-hashed labels, handed off between
+/// producer threads and the single consumer thread.
+///
+/// Capacity must be a power of two and must exceed the lookahead window
+/// producers are allowed to run ahead of the consumer, so a slot is
+/// never reused before it has been drained.
+pub struct LabelRing<T> {
+    slots: UnsafeSlice<Option<T>>,
+    // One flag per slot rather than a single "produced up to N" counter:
+    // producers claim nodes in order but don't necessarily *finish*
+    // hashing them in order, so completion has to be tracked per-node.
+    ready: Box<[AtomicBool]>,
+    mask: usize,
+}
+
+impl<T> LabelRing<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(
+            capacity.is_power_of_two(),
+            "ring capacity must be a power of two"
+        );
+        LabelRing {
+            slots: UnsafeSlice::new(capacity, || None),
+            ready: (0..capacity).map(|_| AtomicBool::new(false)).collect(),
+            mask: capacity - 1,
+        }
+    }
+
+    /// Called by a producer once `node`'s label is ready. Only one
+    /// producer may ever call this for a given `node`.
+    pub fn produce(&self, node: usize, value: T) {
+        let slot = node & self.mask;
+        unsafe { self.slots.replace(slot, Some(value)) };
+        self.ready[slot].store(true, Ordering::Release);
+    }
+
+    pub fn is_ready(&self, node: usize) -> bool {
+        self.ready[node & self.mask].load(Ordering::Acquire)
+    }
+
+    /// Take the value a producer left for `node`.
+    ///
+    /// Panics if `node` hasn't been produced yet; callers must check
+    /// `is_ready` first.
+    pub fn take(&self, node: usize) -> T {
+        let slot = node & self.mask;
+        self.ready[slot].store(false, Ordering::Relaxed);
+        unsafe { self.slots.replace(slot, None) }.expect("consumer ran ahead of its producers")
+    }
+}
+
+/// Drive a produce/consume pipeline over node indices `0..nodes`.
+///
+/// One producer thread per entry in `core_group` is pinned to that core
+/// and pulls the next unclaimed node from a shared cursor, computing
+/// `produce(node, &written)` for it. Producers throttle themselves to
+/// stay within the ring's capacity (`lookahead`, raised to at least one
+/// slot per producer and rounded up to a power of two) of the consumer
+/// so the ring never wraps
+/// onto a slot that hasn't been drained yet; `produce` additionally gets
+/// a `WriteBarrier` it must use to wait for any *specific* node whose
+/// live data it reads (a base parent), since the coarse lookahead
+/// throttle alone doesn't guarantee that particular node's write-back
+/// has landed yet. The calling thread is the consumer: it drains nodes
+/// in strict order, handing each finished label to `consume`, and only
+/// then -- once `consume` has had a chance to write the node back --
+/// advances the barrier past it.
+pub fn run_pipelined<T, P, C>(
+    nodes: usize,
+    core_group: &[CoreIndex],
+    lookahead: usize,
+    produce: P,
+    mut consume: C,
+) where
+    T: Send,
+    P: Fn(usize, &WriteBarrier) -> T + Sync,
+    C: FnMut(usize, T),
+{
+    let capacity = lookahead.max(core_group.len()).next_power_of_two();
+    let ring = LabelRing::with_capacity(capacity);
+    let next_to_claim = AtomicUsize::new(0);
+    let consumed = AtomicUsize::new(0);
+    let written = WriteBarrier::new();
+
+    cb_thread::scope(|scope| {
+        for core in core_group {
+            let core = *core;
+            let ring = &ring;
+            let produce = &produce;
+            let next_to_claim = &next_to_claim;
+            let consumed = &consumed;
+            let written = &written;
+            scope.spawn(move |_| {
+                let _ = bind_core(core);
+                loop {
+                    let node = next_to_claim.fetch_add(1, Ordering::SeqCst);
+                    if node >= nodes {
+                        break;
+                    }
+                    while node >= consumed.load(Ordering::Acquire) + capacity {
+                        std::thread::yield_now();
+                    }
+                    let value = produce(node, written);
+                    ring.produce(node, value);
+                }
+            });
+        }
+
+        for node in 0..nodes {
+            while !ring.is_ready(node) {
+                std::thread::yield_now();
+            }
+            let value = ring.take(node);
+            consume(node, value);
+            consumed.store(node + 1, Ordering::Release);
+            written.advance_past(node);
+        }
+    })
+    .expect("a labeling producer thread panicked");
+}